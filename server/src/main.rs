@@ -1,21 +1,26 @@
+use async_trait::async_trait;
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Query, Request,
     },
     http::{header, Method, StatusCode},
+    middleware::{self, Next},
     response::{
         sse::{Event, KeepAlive, Sse},
         IntoResponse, Response,
     },
-    routing::{any, get, post},
+    routing::{any, delete, get, post},
     Json, Router,
 };
 use bytes::BytesMut;
 use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use image::GenericImageView;
 use portable_pty::{native_pty_system, CommandBuilder, PtySize};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
 
     convert::Infallible,
@@ -26,8 +31,7 @@ use std::{
     sync::{Arc, Condvar, Mutex},
     time::Duration,
 };
-use tokio::sync::mpsc;
-
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 use tower_http::cors::CorsLayer;
 
 // ─── CLI ───────────────────────────────────────────────────────────────────
@@ -46,6 +50,60 @@ struct Cli {
     /// Frontend static files directory
     #[arg(long, default_value = "../frontend/dist", env = "STATIC_DIR")]
     static_dir: PathBuf,
+
+    /// Shared secret clients must present to connect; auth is disabled when unset
+    #[arg(long, env = "AUTH_TOKEN")]
+    auth_token: Option<String>,
+
+    /// Root directory the /api/fs endpoints are confined to; defaults to
+    /// the shell's tracked working directory when unset
+    #[arg(long, env = "FS_ROOT")]
+    fs_root: Option<PathBuf>,
+
+    /// Language servers exposed over /lsp, e.g.
+    /// "rust:rust-analyzer;python:pyright-langserver --stdio"
+    #[arg(long, env = "LSP_SERVERS")]
+    lsp_servers: Option<String>,
+
+    /// Where uploaded screenshots are persisted: "local" or "s3"
+    #[arg(long, default_value = "local", env = "STORAGE_BACKEND")]
+    storage_backend: String,
+
+    /// Directory used by the local storage backend
+    #[arg(long, default_value = "/tmp/ttyd_images", env = "UPLOAD_DIR")]
+    upload_dir: PathBuf,
+
+    /// S3-compatible endpoint URL, required when storage-backend is "s3"
+    #[arg(long, env = "S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+
+    /// Bucket uploaded screenshots are stored in, required for "s3"
+    #[arg(long, env = "S3_BUCKET")]
+    s3_bucket: Option<String>,
+
+    /// Region used when signing S3 requests
+    #[arg(long, default_value = "us-east-1", env = "S3_REGION")]
+    s3_region: String,
+
+    /// S3 access key id, required for "s3"
+    #[arg(long, env = "S3_ACCESS_KEY")]
+    s3_access_key: Option<String>,
+
+    /// S3 secret access key, required for "s3"
+    #[arg(long, env = "S3_SECRET_KEY")]
+    s3_secret_key: Option<String>,
+
+    /// sled database tracking upload dedup/delete-token state
+    #[arg(long, default_value = "/tmp/ttyd_uploads.sled", env = "UPLOAD_DB_PATH")]
+    upload_db_path: PathBuf,
+
+    /// sled database caching computed git diffs
+    #[arg(
+        long,
+        default_value = "/tmp/ttyd_diff_cache.sled",
+        env = "DIFF_CACHE_PATH"
+    )]
+    diff_cache_path: PathBuf,
 }
 
 // ─── Shared State ──────────────────────────────────────────────────────────
@@ -55,6 +113,13 @@ struct AppState {
     shell: String,
     static_dir: PathBuf,
     client_tty: Arc<Mutex<Option<String>>>,
+    auth_token: Option<String>,
+    sessions: Arc<SessionManager>,
+    fs_root: Option<PathBuf>,
+    lsp_servers: Arc<std::collections::HashMap<String, Vec<String>>>,
+    image_store: Arc<dyn ImageStore>,
+    uploads: Arc<UploadRegistry>,
+    diff_cache: Arc<DiffCache>,
 }
 
 // ─── Main ──────────────────────────────────────────────────────────────────
@@ -73,8 +138,41 @@ async fn main() {
         shell: cli.shell.clone(),
         static_dir: cli.static_dir.clone(),
         client_tty: Arc::new(Mutex::new(None)),
+        auth_token: cli.auth_token.clone(),
+        sessions: Arc::new(SessionManager::default()),
+        fs_root: cli.fs_root.clone(),
+        lsp_servers: Arc::new(
+            cli.lsp_servers
+                .as_deref()
+                .map(parse_lsp_servers)
+                .unwrap_or_default(),
+        ),
+        image_store: build_image_store(&cli),
+        uploads: Arc::new(
+            UploadRegistry::open(&cli.upload_db_path)
+                .expect("failed to open upload registry database"),
+        ),
+        diff_cache: Arc::new(
+            DiffCache::open(&cli.diff_cache_path).expect("failed to open diff cache database"),
+        ),
     };
 
+    // Background sweeper: kill sessions nobody has touched in a while.
+    let sweeper_sessions = state.sessions.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            sweeper_sessions.sweep_idle();
+        }
+    });
+
+    if state.auth_token.is_some() {
+        tracing::info!("Auth token configured; /ws and /api/* require matching credentials");
+    } else {
+        tracing::warn!("No auth token configured; /ws and /api/* are open to anyone who can reach this port");
+    }
+
     // Build router
     let app = build_router(state);
 
@@ -120,32 +218,108 @@ fn build_router(state: AppState) -> Router {
 
     let cors = CorsLayer::new()
         .allow_origin(tower_http::cors::Any)
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]);
 
+    // All /api/* routes require a bearer token when one is configured; /ws
+    // gates itself in handle_terminal since the token travels in the init
+    // message rather than a header.
+    let api_routes = Router::new()
+        .route("/health", get(api_health))
+        .route("/client-tty", get(api_client_tty))
+        .route("/cwd", get(api_cwd))
+        .route("/diff", get(api_diff))
+        .route("/git/branches", get(api_git_branches))
+        .route("/git/checkout", get(api_git_checkout))
+        .route("/tmux/list", get(api_tmux_list))
+        .route("/tmux/switch", get(api_tmux_switch))
+        .route("/tmux/create", get(api_tmux_create))
+        .route("/tmux/kill", get(api_tmux_kill))
+        .route("/tmux/detach", get(api_tmux_detach))
+        .route("/events", get(api_events))
+        .route("/upload-image", post(api_upload_image))
+        .route("/upload-image/{id}", get(api_get_image).delete(api_delete_image))
+        .route("/sessions", get(api_sessions_list))
+        .route("/sessions/kill", get(api_sessions_kill))
+        .route("/fs/list", get(api_fs_list))
+        .route("/fs/read", get(api_fs_read))
+        .route("/fs/write", post(api_fs_write))
+        .route("/watch", get(api_watch))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_auth));
+
     Router::new()
         // WebSocket terminal
         .route("/ws", any(ws_handler))
-        // API endpoints
-        .route("/api/health", get(api_health))
-        .route("/api/client-tty", get(api_client_tty))
-        .route("/api/cwd", get(api_cwd))
-        .route("/api/diff", get(api_diff))
-        .route("/api/git/branches", get(api_git_branches))
-        .route("/api/git/checkout", get(api_git_checkout))
-        .route("/api/tmux/list", get(api_tmux_list))
-        .route("/api/tmux/switch", get(api_tmux_switch))
-        .route("/api/tmux/create", get(api_tmux_create))
-        .route("/api/tmux/kill", get(api_tmux_kill))
-        .route("/api/tmux/detach", get(api_tmux_detach))
-        .route("/api/events", get(api_events))
-        .route("/api/upload-image", post(api_upload_image))
+        // LSP proxy: bridges a WebSocket to a spawned language server process
+        .route("/lsp", any(lsp_handler))
+        .nest("/api", api_routes)
         // Static file serving — catch-all for frontend
         .fallback(move |req: Request| serve_static(req, static_dir.clone()))
         .layer(cors)
         .with_state(state)
 }
 
+// ─── Auth ──────────────────────────────────────────────────────────────────
+
+/// Rejects `/api/*` requests that don't carry a matching `Authorization:
+/// Bearer <token>` header. A no-op when no token is configured.
+async fn require_auth(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = state.auth_token.as_deref() else {
+        return next.run(req).await;
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+            next.run(req).await
+        }
+        _ => json_error(
+            "unauthorized",
+            "Missing or invalid bearer token",
+            StatusCode::UNAUTHORIZED,
+        ),
+    }
+}
+
+/// Compares two byte strings in time independent of where they first differ,
+/// so a timing attack can't be used to guess the auth token byte-by-byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn constant_time_eq_accepts_only_exact_matches() {
+        assert!(constant_time_eq(b"secret-token", b"secret-token"));
+        assert!(!constant_time_eq(b"secret-token", b"secret-tokeX"));
+        // Different lengths must be rejected up front rather than comparing
+        // a truncated/extended slice and accidentally matching.
+        assert!(!constant_time_eq(b"secret-token", b"secret-toke"));
+        assert!(!constant_time_eq(b"", b"secret-token"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
+
 // ═══════════════════════════════════════════════════════════════════════════
 // STATIC FILE SERVING
 // ═══════════════════════════════════════════════════════════════════════════
@@ -171,26 +345,202 @@ async fn serve_static(req: Request, static_dir: PathBuf) -> Response {
 
 async fn serve_file(path: &Path) -> Response {
     match tokio::fs::read(path).await {
-        Ok(contents) => {
-            let mime = match path.extension().and_then(|e| e.to_str()) {
-                Some("html") => "text/html; charset=utf-8",
-                Some("js") => "application/javascript; charset=utf-8",
-                Some("css") => "text/css; charset=utf-8",
-                Some("json") => "application/json",
-                Some("png") => "image/png",
-                Some("jpg" | "jpeg") => "image/jpeg",
-                Some("svg") => "image/svg+xml",
-                Some("woff2") => "font/woff2",
-                Some("woff") => "font/woff",
-                Some("ico") => "image/x-icon",
-                _ => "application/octet-stream",
-            };
-            ([(header::CONTENT_TYPE, mime)], contents).into_response()
-        }
+        Ok(contents) => ([(header::CONTENT_TYPE, mime_for_path(path))], contents).into_response(),
         Err(_) => StatusCode::NOT_FOUND.into_response(),
     }
 }
 
+fn mime_for_path(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+// ═══════════════════════════════════════════════════════════════════════════
+// TERMINAL SESSION REGISTRY
+// ═══════════════════════════════════════════════════════════════════════════
+//
+// A `TerminalSession` owns a PTY independent of any one WebSocket. Clients
+// attach by sending a known `session_id` in the init message and are handed
+// the last bit of scrollback plus a live feed; dropping the socket (a flaky
+// mobile connection, a phone lock screen) no longer kills the shell — only
+// `/api/sessions/kill` or the idle sweeper does.
+
+const SCROLLBACK_LIMIT: usize = 64 * 1024;
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+struct TerminalSession {
+    id: String,
+    shell: String,
+    master: Arc<Mutex<Box<dyn portable_pty::MasterPty + Send>>>,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    child: Mutex<Box<dyn portable_pty::Child + Send>>,
+    paused: Arc<(Mutex<bool>, Condvar)>,
+    output_tx: tokio::sync::broadcast::Sender<Vec<u8>>,
+    scrollback: Mutex<Vec<u8>>,
+    created_at: std::time::Instant,
+    last_activity: Mutex<std::time::Instant>,
+}
+
+impl TerminalSession {
+    fn touch(&self) {
+        if let Ok(mut t) = self.last_activity.lock() {
+            *t = std::time::Instant::now();
+        }
+    }
+
+    fn push_scrollback(&self, data: &[u8]) {
+        if let Ok(mut buf) = self.scrollback.lock() {
+            buf.extend_from_slice(data);
+            if buf.len() > SCROLLBACK_LIMIT {
+                let excess = buf.len() - SCROLLBACK_LIMIT;
+                buf.drain(0..excess);
+            }
+        }
+    }
+
+    fn scrollback_snapshot(&self) -> Vec<u8> {
+        self.scrollback.lock().map(|b| b.clone()).unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct SessionManager {
+    sessions: Mutex<std::collections::HashMap<String, Arc<TerminalSession>>>,
+}
+
+impl SessionManager {
+    fn insert(&self, session: Arc<TerminalSession>) {
+        if let Ok(mut map) = self.sessions.lock() {
+            map.insert(session.id.clone(), session);
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<TerminalSession>> {
+        self.sessions.lock().ok().and_then(|map| map.get(id).cloned())
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<TerminalSession>> {
+        self.sessions.lock().ok().and_then(|mut map| map.remove(id))
+    }
+
+    fn list(&self) -> Vec<SessionSummary> {
+        self.sessions
+            .lock()
+            .map(|map| {
+                map.values()
+                    .map(|s| SessionSummary {
+                        id: s.id.clone(),
+                        shell: s.shell.clone(),
+                        age_secs: s.created_at.elapsed().as_secs(),
+                        idle_secs: s
+                            .last_activity
+                            .lock()
+                            .map(|t| t.elapsed().as_secs())
+                            .unwrap_or(0),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn kill(&self, id: &str) -> bool {
+        match self.remove(id) {
+            Some(session) => {
+                if let Ok(mut child) = session.child.lock() {
+                    let _ = child.kill();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn sweep_idle(&self) {
+        let expired: Vec<String> = self
+            .sessions
+            .lock()
+            .map(|map| {
+                map.values()
+                    .filter(|s| {
+                        s.last_activity
+                            .lock()
+                            .map(|t| t.elapsed() > SESSION_IDLE_TIMEOUT)
+                            .unwrap_or(false)
+                    })
+                    .map(|s| s.id.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for id in expired {
+            tracing::info!("Killing idle session {}", id);
+            self.kill(&id);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SessionSummary {
+    id: String,
+    shell: String,
+    age_secs: u64,
+    idle_secs: u64,
+}
+
+// ─── GET /api/sessions ─────────────────────────────────────────────────────
+
+async fn api_sessions_list(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "sessions": state.sessions.list() }))
+}
+
+// ─── GET /api/sessions/kill ────────────────────────────────────────────────
+
+#[derive(Deserialize)]
+struct SessionKillQuery {
+    session_id: Option<String>,
+}
+
+async fn api_sessions_kill(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<SessionKillQuery>,
+) -> Response {
+    let id = match query.session_id {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            return json_error(
+                "missing_session_id",
+                "session_id required",
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+
+    if state.sessions.kill(&id) {
+        Json(serde_json::json!({ "success": true })).into_response()
+    } else {
+        json_error(
+            "not_found",
+            &format!("No session '{}'", id),
+            StatusCode::NOT_FOUND,
+        )
+    }
+}
+
 // ═══════════════════════════════════════════════════════════════════════════
 // WEBSOCKET TERMINAL (ttyd protocol compatible)
 // ═══════════════════════════════════════════════════════════════════════════
@@ -207,24 +557,122 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
     // Step 1: Wait for the auth/init message from client
-    // Client sends: JSON {"AuthToken":"","columns":80,"rows":24}
-    let (init_cols, init_rows) = match ws_receiver.next().await {
+    // Client sends: JSON {"AuthToken":"","columns":80,"rows":24,"session_id":"...",
+    //                      "protocol_version":2,"capabilities":["flow_control"]}
+    let init = match ws_receiver.next().await {
         Some(Ok(msg)) => parse_init_message(msg),
         _ => {
             tracing::error!("No init message received");
             return;
         }
     };
+    let (init_cols, init_rows) = (init.columns, init.rows);
+
+    if let Some(expected) = state.auth_token.as_deref() {
+        let provided = init.auth_token.as_deref().unwrap_or("");
+        if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+            tracing::warn!("Rejecting terminal connection: bad or missing auth token");
+            let _ = ws_sender.send(Message::Close(None)).await;
+            return;
+        }
+    }
+
+    let missing: Vec<&str> = init
+        .required_capabilities
+        .iter()
+        .map(String::as_str)
+        .filter(|c| !SUPPORTED_CAPABILITIES.contains(c))
+        .collect();
+    if !missing.is_empty() {
+        tracing::warn!(
+            "Rejecting terminal connection: client requires unsupported capabilities {:?}",
+            missing
+        );
+        let _ = ws_sender
+            .send(Message::Close(Some(CloseFrame {
+                code: 1008, // policy violation
+                reason: format!("unsupported capabilities: {}", missing.join(", ")).into(),
+            })))
+            .await;
+        return;
+    }
+
+    // Advertise our version and the intersection of what was asked for and
+    // what we actually support, so old and new frontends can interoperate
+    // as the protocol grows instead of silently mis-framing bytes.
+    let granted_capabilities: Vec<&str> = init
+        .capabilities
+        .iter()
+        .map(String::as_str)
+        .filter(|c| SUPPORTED_CAPABILITIES.contains(c))
+        .collect();
+    let hello = serde_json::json!({
+        "type": "hello",
+        "protocol_version": PROTOCOL_VERSION,
+        "capabilities": granted_capabilities,
+    });
+    let _ = ws_sender.send(Message::Text(hello.to_string().into())).await;
+
+    let session_id = init.session_id.filter(|id| !id.is_empty());
+    let existing = session_id.as_deref().and_then(|id| state.sessions.get(id));
+
+    let (session, output_rx) = match existing {
+        Some(session) => {
+            tracing::info!("Re-attaching to session {}", session.id);
+            session.touch();
+
+            // Subscribe before snapshotting scrollback: the PTY reader thread
+            // keeps running between connections, so if we snapshotted first
+            // and subscribed after, anything it pushed in that gap would be
+            // neither in the snapshot nor seen by the new subscriber.
+            let output_rx = session.output_tx.subscribe();
+            let scrollback = session.scrollback_snapshot();
+            if !scrollback.is_empty() {
+                let mut frame = Vec::with_capacity(scrollback.len() + 1);
+                frame.push(0x30);
+                frame.extend_from_slice(&scrollback);
+                let _ = ws_sender.send(Message::Binary(frame.into())).await;
+            }
+
+            if let Ok(m) = session.master.lock() {
+                let _ = m.resize(PtySize {
+                    rows: init_rows,
+                    cols: init_cols,
+                    pixel_width: 0,
+                    pixel_height: 0,
+                });
+            }
+
+            (session, output_rx)
+        }
+        None => {
+            tracing::info!("Terminal session: {}x{}", init_cols, init_rows);
+            match spawn_session(session_id, init_cols, init_rows, &state, &mut ws_sender).await {
+                Some(result) => result,
+                None => return,
+            }
+        }
+    };
 
-    tracing::info!("Terminal session: {}x{}", init_cols, init_rows);
+    pump_session(session, output_rx, ws_sender, ws_receiver, state).await;
+}
 
-    // Step 2: Generate and write wrapper script
+/// Opens a PTY, spawns the configured shell in it, and registers the result
+/// as a new `TerminalSession` so future connections can attach to it.
+async fn spawn_session(
+    requested_id: Option<String>,
+    init_cols: u16,
+    init_rows: u16,
+    state: &AppState,
+    ws_sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
+) -> Option<(Arc<TerminalSession>, tokio::sync::broadcast::Receiver<Vec<u8>>)> {
+    // Generate and write wrapper script
     let wrapper_path = "/tmp/rust_terminal_wrapper.sh";
     let tty_file = "/tmp/ttyd_client_tty";
     let cwd_file = "/tmp/ttyd_cwd";
     write_wrapper_script(wrapper_path, &state.shell, tty_file, cwd_file);
 
-    // Step 3: Spawn PTY
+    // Spawn PTY
     let pty_system = native_pty_system();
     let pair = match pty_system.openpty(PtySize {
         rows: init_rows,
@@ -240,7 +688,7 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
                     format!("\x30Error: Failed to open PTY: {}\r\n", e).into(),
                 ))
                 .await;
-            return;
+            return None;
         }
     };
 
@@ -250,7 +698,7 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
     cmd.env_remove("TMUX");
     cmd.env_remove("TMUX_PANE");
 
-    let _child = match pair.slave.spawn_command(cmd) {
+    let child = match pair.slave.spawn_command(cmd) {
         Ok(child) => child,
         Err(e) => {
             tracing::error!("Failed to spawn shell: {}", e);
@@ -259,7 +707,7 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
                     format!("\x30Error: Failed to spawn shell: {}\r\n", e).into(),
                 ))
                 .await;
-            return;
+            return None;
         }
     };
 
@@ -271,35 +719,59 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
         Ok(r) => r,
         Err(e) => {
             tracing::error!("Failed to clone PTY reader: {}", e);
-            return;
+            return None;
         }
     };
     let pty_writer = match pair.master.take_writer() {
         Ok(w) => w,
         Err(e) => {
             tracing::error!("Failed to take PTY writer: {}", e);
-            return;
+            return None;
         }
     };
-    let pty_writer = Arc::new(Mutex::new(pty_writer));
 
-    // Keep master alive for resize
-    let master = Arc::new(Mutex::new(pair.master));
+    let id = requested_id.unwrap_or_else(|| {
+        format!(
+            "sess_{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        )
+    });
+
+    let (output_tx, _) = tokio::sync::broadcast::channel::<Vec<u8>>(1024);
+
+    let session = Arc::new(TerminalSession {
+        id: id.clone(),
+        shell: state.shell.clone(),
+        master: Arc::new(Mutex::new(pair.master)),
+        writer: Arc::new(Mutex::new(pty_writer)),
+        child: Mutex::new(child),
+        paused: Arc::new((Mutex::new(false), Condvar::new())),
+        output_tx,
+        scrollback: Mutex::new(Vec::new()),
+        created_at: std::time::Instant::now(),
+        last_activity: Mutex::new(std::time::Instant::now()),
+    });
 
-    // Flow control: shared pause signal between PTY reader thread and WebSocket receiver
-    let paused = Arc::new((Mutex::new(false), Condvar::new()));
-    let paused_reader = paused.clone();
+    state.sessions.insert(session.clone());
 
-    // Channel: PTY output → WebSocket sender
-    let (output_tx, mut output_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    // Subscribe before starting the reader thread: otherwise the shell's
+    // early banner/prompt output could be sent before anyone is listening
+    // and would be lost (no scrollback yet to fall back on either).
+    let output_rx = session.output_tx.subscribe();
 
-    // PTY reader thread (blocking I/O → separate thread)
-    let reader_handle = std::thread::spawn(move || {
+    // PTY reader thread (blocking I/O → separate thread). Runs for the
+    // lifetime of the session, not any single WebSocket connection.
+    let reader_session = session.clone();
+    let client_tty_shared = state.client_tty.clone();
+    std::thread::spawn(move || {
         let mut buf = [0u8; 32768];
         loop {
             // Flow control: wait if paused (auto-resume after 2s)
             {
-                let (lock, cvar) = &*paused_reader;
+                let (lock, cvar) = &*reader_session.paused;
                 let mut is_paused = lock.lock().unwrap();
                 if *is_paused {
                     let result = cvar.wait_timeout(is_paused, Duration::from_secs(2)).unwrap();
@@ -313,51 +785,68 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
             match pty_reader.read(&mut buf) {
                 Ok(0) => break,
                 Ok(n) => {
-                    if output_tx.send(buf[..n].to_vec()).is_err() {
-                        break;
-                    }
+                    let chunk = &buf[..n];
+                    detect_client_tty(chunk, &client_tty_shared);
+                    reader_session.push_scrollback(chunk);
+                    reader_session.touch();
+                    // No receivers yet (e.g. a lagging reconnect) is fine —
+                    // the data still lives in scrollback.
+                    let _ = reader_session.output_tx.send(chunk.to_vec());
                 }
                 Err(_) => break,
             }
         }
+        tracing::info!("Session {} PTY closed", reader_session.id);
+        reader_session.output_tx.send(Vec::new()).ok();
     });
 
-    // Client TTY tracking
-    let client_tty_shared = state.client_tty.clone();
+    Some((session, output_rx))
+}
 
-    // Per-connection tty tracking (for safe cleanup independent of global state)
-    // Prevents race condition where a new connection's tty gets detached by old cleanup.
-    let connection_tty: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
-    let connection_tty_sender = connection_tty.clone();
+/// Scans a chunk of PTY output for the OSC 7337 tty-reporting sequence
+/// the wrapper script prints at startup and records the detected tty.
+fn detect_client_tty(chunk: &[u8], client_tty_shared: &Arc<Mutex<Option<String>>>) {
+    let Ok(text) = std::str::from_utf8(chunk) else {
+        return;
+    };
+    let Some(pos) = text.find("]7337;") else {
+        return;
+    };
+    let after = &text[pos + 6..];
+    let Some(end) = after.find('\\') else {
+        return;
+    };
+    let tty = after[..end].trim_end_matches('\x1b');
+    if tty.starts_with("/dev/pts/") {
+        if let Ok(mut lock) = client_tty_shared.lock() {
+            *lock = Some(tty.to_string());
+        }
+    }
+}
 
+/// Bridges one WebSocket connection to a (possibly shared) `TerminalSession`
+/// until either side closes. Multiple connections may run this concurrently
+/// against the same session; the PTY itself is only torn down by
+/// `SessionManager::kill` or the idle sweeper.
+async fn pump_session(
+    session: Arc<TerminalSession>,
+    mut output_rx: tokio::sync::broadcast::Receiver<Vec<u8>>,
+    mut ws_sender: futures_util::stream::SplitSink<WebSocket, Message>,
+    mut ws_receiver: futures_util::stream::SplitStream<WebSocket>,
+    state: AppState,
+) {
     // ── ADAPTIVE BATCHING: WebSocket sender task ──
     // Adaptive batching: 4ms idle flush, 32KB cap.
     let sender_task = tokio::spawn(async move {
         let mut buffer = BytesMut::with_capacity(16384);
-        let mut tty_detected = false;
 
         loop {
             let data = output_rx.recv().await;
             match data {
-                Some(bytes) => {
-                    if !tty_detected {
-                        if let Ok(text) = std::str::from_utf8(&bytes) {
-                            if let Some(pos) = text.find("]7337;") {
-                                let after = &text[pos + 6..];
-                                if let Some(end) = after.find('\\') {
-                                    let tty = after[..end].trim_end_matches('\x1b');
-                                    if tty.starts_with("/dev/pts/") {
-                                if let Ok(mut lock) = client_tty_shared.lock() {
-                                    *lock = Some(tty.to_string());
-                                }
-                                if let Ok(mut lock) = connection_tty_sender.lock() {
-                                    *lock = Some(tty.to_string());
-                                }
-                                tty_detected = true;
-                                    }
-                                }
-                            }
-                        }
+                Ok(bytes) => {
+                    if bytes.is_empty() {
+                        // Sentinel pushed when the PTY reader thread exits.
+                        break;
                     }
                     buffer.extend_from_slice(&bytes);
 
@@ -367,32 +856,23 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
                             biased;
                             more = output_rx.recv() => {
                                 match more {
-                                    Some(more_bytes) => {
-                                        if !tty_detected {
-                                            if let Ok(text) = std::str::from_utf8(&more_bytes) {
-                                                if let Some(pos) = text.find("]7337;") {
-                                                    let after = &text[pos + 6..];
-                                                    if let Some(end) = after.find('\\') {
-                                                        let tty = after[..end].trim_end_matches('\x1b');
-                                                        if tty.starts_with("/dev/pts/") {
-                                if let Ok(mut lock) = client_tty_shared.lock() {
-                                    *lock = Some(tty.to_string());
-                                }
-                                if let Ok(mut lock) = connection_tty_sender.lock() {
-                                    *lock = Some(tty.to_string());
-                                }
-                                tty_detected = true;
-                                                        }
-                                                    }
-                                                }
+                                    Ok(more_bytes) => {
+                                        if more_bytes.is_empty() {
+                                            if !buffer.is_empty() {
+                                                let mut frame = Vec::with_capacity(buffer.len() + 1);
+                                                frame.push(0x30);
+                                                frame.extend_from_slice(&buffer);
+                                                let _ = ws_sender.send(Message::Binary(frame.into())).await;
                                             }
+                                            return;
                                         }
                                         buffer.extend_from_slice(&more_bytes);
                                         if buffer.len() > 32768 {
                                             break;
                                         }
                                     }
-                                    None => {
+                                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => break,
+                                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
                                         if !buffer.is_empty() {
                                             let mut frame = Vec::with_capacity(buffer.len() + 1);
                                             frame.push(0x30);
@@ -419,23 +899,16 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
                         }
                     }
                 }
-                None => {
-                    if !buffer.is_empty() {
-                        let mut frame = Vec::with_capacity(buffer.len() + 1);
-                        frame.push(0x30);
-                        frame.extend_from_slice(&buffer);
-                        let _ = ws_sender.send(Message::Binary(frame.into())).await;
-                    }
-                    break;
-                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
             }
         }
     });
 
     // ── WebSocket receiver task: client → PTY ──
-    let pty_writer_recv = pty_writer.clone();
-    let master_recv = master.clone();
-    let paused_recv = paused.clone();
+    let pty_writer_recv = session.writer.clone();
+    let master_recv = session.master.clone();
+    let paused_recv = session.paused.clone();
 
     let recv_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = ws_receiver.next().await {
@@ -508,55 +981,78 @@ async fn handle_terminal(socket: WebSocket, state: AppState) {
         }
     });
 
-    // Wait for either task to finish
+    // Wait for either task to finish — this only ends the connection, not
+    // the underlying session.
     tokio::select! {
         _ = sender_task => {},
         _ = recv_task => {},
     }
 
-    // Ensure PTY reader thread is unpaused so it can exit cleanly
-    {
-        let (lock, cvar) = &*paused;
-        if let Ok(mut is_paused) = lock.lock() {
-            *is_paused = false;
-            cvar.notify_one();
-        }
-    }
-
-    // Gracefully detach tmux client before the child process is killed,
-    // preventing SIGHUP cascade that can destroy tmux sessions.
-    // Use per-connection tty (not global) to avoid race with concurrent connections.
-    let cleanup_tty = connection_tty.lock().ok().and_then(|lock| lock.clone());
+    // Gracefully detach tmux client before cleanup, preventing SIGHUP
+    // cascade that can destroy tmux sessions. Best-effort; other
+    // connections may still be attached to this session.
+    let cleanup_tty = state.client_tty.lock().ok().and_then(|lock| lock.clone());
     if let Some(ref tty) = cleanup_tty {
         if let Err(e) = run_cmd("tmux", &["detach-client", "-t", tty]) {
             tracing::warn!("tmux detach-client failed: {}", e);
         }
-        std::thread::sleep(std::time::Duration::from_millis(100));
     }
-    // Only clear global tty if it still belongs to this connection (compare-and-swap)
-    if let Ok(mut lock) = state.client_tty.lock() {
-        if *lock == cleanup_tty {
-            *lock = None;
+
+    tracing::info!("Connection to session {} ended", session.id);
+}
+
+/// Protocol version this server speaks. Bumped whenever the init-message or
+/// control-frame shape changes in a way clients might care about.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Capabilities this server can actually negotiate. Keep in sync with
+/// whatever `handle_terminal` and friends actually implement.
+const SUPPORTED_CAPABILITIES: &[&str] = &["flow_control", "session_resume", "fs", "lsp"];
+
+struct ParsedInit {
+    columns: u16,
+    rows: u16,
+    auth_token: Option<String>,
+    session_id: Option<String>,
+    capabilities: Vec<String>,
+    required_capabilities: Vec<String>,
+}
+
+impl Default for ParsedInit {
+    fn default() -> Self {
+        ParsedInit {
+            columns: 80,
+            rows: 24,
+            auth_token: None,
+            session_id: None,
+            capabilities: Vec::new(),
+            required_capabilities: Vec::new(),
         }
     }
-
-    let _ = reader_handle;
-    tracing::info!("Terminal session ended");
 }
 
-fn parse_init_message(msg: Message) -> (u16, u16) {
+fn parse_init_message(msg: Message) -> ParsedInit {
     let data = match msg {
         Message::Text(text) => text.as_bytes().to_vec(),
         Message::Binary(data) => data.to_vec(),
-        _ => return (80, 24),
+        _ => return ParsedInit::default(),
     };
 
-    if let Ok(text) = std::str::from_utf8(&data) {
-        if let Ok(init) = serde_json::from_str::<InitMessage>(text) {
-            return (init.columns.max(1) as u16, init.rows.max(1) as u16);
-        }
+    let Ok(text) = std::str::from_utf8(&data) else {
+        return ParsedInit::default();
+    };
+    let Ok(init) = serde_json::from_str::<InitMessage>(text) else {
+        return ParsedInit::default();
+    };
+
+    ParsedInit {
+        columns: init.columns.max(1) as u16,
+        rows: init.rows.max(1) as u16,
+        auth_token: init.auth_token,
+        session_id: init.session_id,
+        capabilities: init.capabilities,
+        required_capabilities: init.required_capabilities,
     }
-    (80, 24)
 }
 
 fn write_wrapper_script(path: &str, shell: &str, tty_file: &str, cwd_file: &str) {
@@ -652,10 +1148,27 @@ exec {}
 struct InitMessage {
     #[serde(default)]
     #[serde(alias = "AuthToken")]
-    #[allow(dead_code)]
     auth_token: Option<String>,
     columns: u32,
     rows: u32,
+    /// When set and known, re-attach to this session instead of spawning a
+    /// new shell.
+    #[serde(default)]
+    session_id: Option<String>,
+    /// Client's protocol version, informational only today — the server
+    /// always replies with its own version in the `hello` control frame.
+    #[serde(default)]
+    #[allow(dead_code)]
+    protocol_version: Option<u32>,
+    /// Capabilities the client would like, if supported; unsupported ones
+    /// are silently dropped from the server's `hello` reply.
+    #[serde(default)]
+    capabilities: Vec<String>,
+    /// Capabilities the client cannot function without; if any aren't
+    /// supported the connection is closed with policy-violation (1008)
+    /// before a PTY is ever spawned.
+    #[serde(default)]
+    required_capabilities: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -669,62 +1182,242 @@ struct ResizeMessage {
 }
 
 // ═══════════════════════════════════════════════════════════════════════════
-// HTTP API HANDLERS
+// LSP PROXY (/lsp)
 // ═══════════════════════════════════════════════════════════════════════════
-
-// ─── JSON helpers ──────────────────────────────────────────────────────────
-
-fn json_response<T: Serialize>(data: &T) -> Response {
-    Json(data).into_response()
-}
-
-fn json_error(error: &str, message: &str, status: StatusCode) -> Response {
-    (
-        status,
-        Json(serde_json::json!({ "error": error, "message": message })),
-    )
-        .into_response()
+//
+// Bridges a browser WebSocket to a language server's stdio. LSP over stdio
+// frames each JSON-RPC message with `Content-Length: <n>\r\n\r\n<body>`;
+// browsers want one JSON message per WS text frame. We translate between
+// the two in both directions and kill the child when the socket closes.
+
+/// Parses `LSP_SERVERS`/`--lsp-servers`, e.g.
+/// "rust:rust-analyzer;python:pyright-langserver --stdio", into a
+/// lang → argv map.
+fn parse_lsp_servers(spec: &str) -> std::collections::HashMap<String, Vec<String>> {
+    spec.split(';')
+        .filter_map(|entry| {
+            let (lang, cmd) = entry.trim().split_once(':')?;
+            let argv: Vec<String> = cmd.split_whitespace().map(|s| s.to_string()).collect();
+            if lang.trim().is_empty() || argv.is_empty() {
+                None
+            } else {
+                Some((lang.trim().to_string(), argv))
+            }
+        })
+        .collect()
 }
 
-// ─── GET /api/health ───────────────────────────────────────────────────────
-
-async fn api_health() -> Json<serde_json::Value> {
-    Json(serde_json::json!({ "status": "ok" }))
+#[derive(Deserialize)]
+struct LspQuery {
+    lang: Option<String>,
+    #[serde(default)]
+    #[allow(dead_code)]
+    token: Option<String>,
 }
 
-// ─── GET /api/client-tty ───────────────────────────────────────────────────
-
-async fn api_client_tty(
+async fn lsp_handler(
+    ws: WebSocketUpgrade,
+    Query(query): Query<LspQuery>,
     axum::extract::State(state): axum::extract::State<AppState>,
-) -> Json<serde_json::Value> {
-    let tty = get_client_tty_from_state(&state);
-    Json(serde_json::json!({ "client_tty": tty }))
-}
-
-fn get_client_tty_from_state(state: &AppState) -> Option<String> {
-    // First try from our stored state
-    if let Ok(lock) = state.client_tty.lock() {
-        if let Some(ref tty) = *lock {
-            return Some(tty.clone());
+) -> Response {
+    if let Some(expected) = state.auth_token.as_deref() {
+        let provided = query.token.as_deref().unwrap_or("");
+        if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+            return json_error(
+                "unauthorized",
+                "Missing or invalid token",
+                StatusCode::UNAUTHORIZED,
+            );
         }
     }
-    // Fallback: read from file
-    get_client_tty_from_file()
+
+    let lang = match query.lang {
+        Some(l) if !l.is_empty() => l,
+        _ => {
+            return json_error(
+                "missing_lang",
+                "lang query param required",
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+
+    let argv = match state.lsp_servers.get(&lang) {
+        Some(argv) => argv.clone(),
+        None => {
+            return json_error(
+                "unsupported_language",
+                &format!("No language server configured for '{}'", lang),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+
+    ws.on_upgrade(move |socket| bridge_lsp(socket, lang, argv))
 }
 
-fn get_client_tty_from_file() -> Option<String> {
-    let tty_from_file = std::fs::read_to_string("/tmp/ttyd_client_tty")
-        .ok()
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty());
+async fn bridge_lsp(socket: WebSocket, lang: String, argv: Vec<String>) {
+    let (mut ws_sender, mut ws_receiver) = socket.split();
 
-    // Verify against current tmux clients
-    if let Ok(output) = run_cmd("tmux", &["list-clients", "-F", "#{client_tty}"]) {
-        let clients: Vec<&str> = output.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect();
+    let Some((program, args)) = argv.split_first() else {
+        let _ = ws_sender.send(Message::Close(None)).await;
+        return;
+    };
 
-        if let Some(ref tty) = tty_from_file {
-            if clients.contains(&tty.as_str()) {
-                return Some(tty.clone());
+    let mut child = match tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::error!("Failed to spawn language server '{}': {}", lang, e);
+            let _ = ws_sender
+                .send(Message::Text(
+                    serde_json::json!({ "error": format!("failed to spawn '{}': {}", lang, e) })
+                        .to_string()
+                        .into(),
+                ))
+                .await;
+            return;
+        }
+    };
+
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let stdout = child.stdout.take().expect("piped stdout");
+
+    // server stdout → WS: decode Content-Length framing, forward each body
+    // as a single WS text frame.
+    let reader_task = tokio::spawn(async move {
+        let mut reader = tokio::io::BufReader::new(stdout);
+        loop {
+            match read_lsp_frame(&mut reader).await {
+                Ok(Some(body)) => {
+                    if ws_sender.send(Message::Text(body.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("LSP '{}' stdout framing error: {}", lang, e);
+                    break;
+                }
+            }
+        }
+        let _ = ws_sender.close().await;
+    });
+
+    // WS → server stdin: wrap each inbound JSON text frame with a
+    // Content-Length header before writing it to the child.
+    while let Some(Ok(msg)) = ws_receiver.next().await {
+        match msg {
+            Message::Text(text) => {
+                let framed = format!("Content-Length: {}\r\n\r\n{}", text.len(), text);
+                if stdin.write_all(framed.as_bytes()).await.is_err() {
+                    break;
+                }
+                let _ = stdin.flush().await;
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    reader_task.abort();
+    let _ = child.start_kill();
+    tracing::info!("LSP bridge for '{}' closed", lang);
+}
+
+/// Reads one `Content-Length`-framed LSP message off `reader`: accumulates
+/// header lines until a blank line, then reads exactly that many body
+/// bytes. Returns `Ok(None)` on EOF between messages.
+async fn read_lsp_frame<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<String>> {
+    let mut content_length: Option<usize> = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some(value) = trimmed.strip_prefix("Content-Length:") {
+            content_length = value.trim().parse().ok();
+        }
+    }
+
+    let len = match content_length {
+        Some(len) => len,
+        None => return Ok(Some(String::new())),
+    };
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(String::from_utf8_lossy(&body).to_string()))
+}
+
+// ═══════════════════════════════════════════════════════════════════════════
+// HTTP API HANDLERS
+// ═══════════════════════════════════════════════════════════════════════════
+
+// ─── JSON helpers ──────────────────────────────────────────────────────────
+
+fn json_response<T: Serialize>(data: &T) -> Response {
+    Json(data).into_response()
+}
+
+fn json_error(error: &str, message: &str, status: StatusCode) -> Response {
+    (
+        status,
+        Json(serde_json::json!({ "error": error, "message": message })),
+    )
+        .into_response()
+}
+
+// ─── GET /api/health ───────────────────────────────────────────────────────
+
+async fn api_health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// ─── GET /api/client-tty ───────────────────────────────────────────────────
+
+async fn api_client_tty(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Json<serde_json::Value> {
+    let tty = get_client_tty_from_state(&state);
+    Json(serde_json::json!({ "client_tty": tty }))
+}
+
+fn get_client_tty_from_state(state: &AppState) -> Option<String> {
+    // First try from our stored state
+    if let Ok(lock) = state.client_tty.lock() {
+        if let Some(ref tty) = *lock {
+            return Some(tty.clone());
+        }
+    }
+    // Fallback: read from file
+    get_client_tty_from_file()
+}
+
+fn get_client_tty_from_file() -> Option<String> {
+    let tty_from_file = std::fs::read_to_string("/tmp/ttyd_client_tty")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+
+    // Verify against current tmux clients
+    if let Ok(output) = run_cmd("tmux", &["list-clients", "-F", "#{client_tty}"]) {
+        let clients: Vec<&str> = output.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect();
+
+        if let Some(ref tty) = tty_from_file {
+            if clients.contains(&tty.as_str()) {
+                return Some(tty.clone());
             }
         }
         if clients.len() == 1 {
@@ -1086,10 +1779,144 @@ fn parse_unified_diff(raw: &str, changed_files: &[ChangedFile]) -> DiffResult {
     }
 }
 
+// ─── Diff result cache (sled) ───────────────────────────────────────────────
+
+/// Caches `DiffResult` keyed on what can actually invalidate it: the repo
+/// root, the commit HEAD points at, the index's mtime (which changes
+/// on every `git add`, including the `add -N` that `get_files_diff` runs
+/// to pick up new files), and the latest mtime among modified/untracked
+/// files (`git_worktree_mtime`). `/api/diff` only ever runs a plain
+/// `git diff` (working tree against the index), so `base_rev` and
+/// `head_rev` are currently the same value (HEAD) — kept as two fields
+/// since that's the key shape this is storing against. Backed by an embedded sled tree so repeated views of an
+/// unchanged working tree are free even across restarts.
+struct DiffCache {
+    tree: sled::Tree,
+}
+
+impl DiffCache {
+    fn open(path: &Path) -> sled::Result<Self> {
+        Ok(DiffCache {
+            tree: sled::open(path)?.open_tree("diff_cache")?,
+        })
+    }
+
+    fn key(
+        repo_root: &str,
+        base_rev: &str,
+        head_rev: &str,
+        index_mtime: u128,
+        worktree_mtime: u128,
+    ) -> String {
+        format!(
+            "{}|{}|{}|{}|{}",
+            repo_root, base_rev, head_rev, index_mtime, worktree_mtime
+        )
+    }
+
+    /// Drops every cached entry for `repo_root`. The reactive watcher
+    /// (which sees every fs change) calls this after each debounced burst,
+    /// which also covers working-tree changes the key's `worktree_mtime`
+    /// misses, such as a deleted file (it has no mtime to read).
+    fn invalidate_repo(&self, repo_root: &str) {
+        let prefix = format!("{}|", repo_root);
+        for key in self.tree.scan_prefix(prefix.as_bytes()).keys().flatten() {
+            let _ = self.tree.remove(key);
+        }
+    }
+
+    fn get(
+        &self,
+        repo_root: &str,
+        base_rev: &str,
+        head_rev: &str,
+        index_mtime: u128,
+        worktree_mtime: u128,
+    ) -> Option<DiffResult> {
+        let key = Self::key(repo_root, base_rev, head_rev, index_mtime, worktree_mtime);
+        self.tree
+            .get(key)
+            .ok()
+            .flatten()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+    }
+
+    fn put(
+        &self,
+        repo_root: &str,
+        base_rev: &str,
+        head_rev: &str,
+        index_mtime: u128,
+        worktree_mtime: u128,
+        result: &DiffResult,
+    ) {
+        // Only the latest state of a given repo is ever worth keeping
+        // around — drop older entries so the tree doesn't grow by one
+        // entry per commit/stage forever.
+        self.invalidate_repo(repo_root);
+        let key = Self::key(repo_root, base_rev, head_rev, index_mtime, worktree_mtime);
+        if let Ok(bytes) = serde_json::to_vec(result) {
+            let _ = self.tree.insert(key, bytes);
+        }
+    }
+}
+
+/// The commit HEAD currently points at, or an empty string in a repo with
+/// no commits yet (the cache key then rests on the index and working-tree
+/// mtimes alone).
+fn git_head_rev(git_root: &str) -> String {
+    run_cmd_in("git", &["rev-parse", "HEAD"], git_root)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// The index file's mtime in nanoseconds since the epoch, or 0 if it
+/// can't be read (e.g. no commits yet) — either way, a value that changes
+/// whenever `git add`/`git commit` touches the index.
+fn git_index_mtime(git_root: &str) -> u128 {
+    std::fs::metadata(Path::new(git_root).join(".git").join("index"))
+        .and_then(|m| m.modified())
+        .map(|t| {
+            t.duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        })
+        .unwrap_or(0)
+}
+
+/// Latest mtime among files `git` considers modified or untracked — the
+/// same set of files that would change what `get_files_diff` returns.
+/// Unlike `git_index_mtime`, this moves on a plain save-in-place edit, not
+/// just `git add`/`commit`, so `/api/diff` can detect a stale cache entry
+/// on its own even when no `/api/events` watcher is running to call
+/// `invalidate_repo` on its behalf.
+fn git_worktree_mtime(git_root: &str) -> u128 {
+    let status = run_cmd_in_with_timeout(
+        "git",
+        &["status", "--porcelain", "-z", "--untracked-files=normal"],
+        git_root,
+        DIFF_CMD_TIMEOUT,
+    )
+    .unwrap_or_default();
+
+    status
+        .split('\0')
+        .filter(|entry| entry.len() > 3)
+        .filter_map(|entry| std::fs::metadata(Path::new(git_root).join(&entry[3..])).ok())
+        .filter_map(|meta| meta.modified().ok())
+        .map(|t| {
+            t.duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
 fn get_files_diff(git_root: &str) -> DiffResult {
-    let _ = run_cmd_in("git", &["add", "-N", "."], git_root);
+    let _ = run_cmd_in_with_timeout("git", &["add", "-N", "."], git_root, DIFF_CMD_TIMEOUT);
 
-    let raw = match run_cmd_in("git", &["diff", "-U3"], git_root) {
+    let raw = match run_cmd_in_with_timeout("git", &["diff", "-U3"], git_root, DIFF_CMD_TIMEOUT) {
         Ok(o) => o,
         Err(_) => return DiffResult {
             files: vec![],
@@ -1120,18 +1947,52 @@ async fn api_diff(
     let git_root = get_git_root(&cwd);
     let branch = get_branch(&git_root);
 
-    // Run diff in blocking task (subprocess I/O)
+    // Run diff (and its cache lookup, also subprocess/disk I/O) in a
+    // blocking task.
     let git_root_clone = git_root.clone();
-    let diff_data = tokio::task::spawn_blocking(move || get_files_diff(&git_root_clone))
-        .await
-        .unwrap_or_else(|_| DiffResult {
-            files: vec![],
-            summary: DiffSummary {
-                total_files: 0,
-                total_additions: 0,
-                total_deletions: 0,
-            },
-        });
+    let diff_cache = state.diff_cache.clone();
+    let diff_data = tokio::task::spawn_blocking(move || {
+        // `get_files_diff` stages new files with `add -N` before diffing,
+        // which bumps the index's mtime — run that first so the mtime we
+        // key the cache on matches what every subsequent call will see,
+        // instead of reading it pre-add and guaranteeing a miss next time.
+        let _ =
+            run_cmd_in_with_timeout("git", &["add", "-N", "."], &git_root_clone, DIFF_CMD_TIMEOUT);
+
+        let head_rev = git_head_rev(&git_root_clone);
+        let index_mtime = git_index_mtime(&git_root_clone);
+        let worktree_mtime = git_worktree_mtime(&git_root_clone);
+
+        if let Some(cached) = diff_cache.get(
+            &git_root_clone,
+            &head_rev,
+            &head_rev,
+            index_mtime,
+            worktree_mtime,
+        ) {
+            return cached;
+        }
+
+        let result = get_files_diff(&git_root_clone);
+        diff_cache.put(
+            &git_root_clone,
+            &head_rev,
+            &head_rev,
+            index_mtime,
+            worktree_mtime,
+            &result,
+        );
+        result
+    })
+    .await
+    .unwrap_or_else(|_| DiffResult {
+        files: vec![],
+        summary: DiffSummary {
+            total_files: 0,
+            total_additions: 0,
+            total_deletions: 0,
+        },
+    });
 
     Json(serde_json::json!({
         "cwd": cwd,
@@ -1430,7 +2291,10 @@ async fn api_tmux_detach(
     }
 }
 
-// ─── GET /api/events (SSE) ─────────────────────────────────────────────────
+// ─── GET /api/events (SSE, reactive git/tmux watcher) ──────────────────────
+
+const EVENTS_DEBOUNCE: Duration = Duration::from_millis(100);
+const EVENTS_FALLBACK_POLL: Duration = Duration::from_secs(2);
 
 #[derive(Deserialize)]
 struct EventsQuery {
@@ -1441,49 +2305,16 @@ async fn api_events(
     axum::extract::State(state): axum::extract::State<AppState>,
     Query(query): Query<EventsQuery>,
 ) -> Sse<impl futures_util::Stream<Item = Result<Event, Infallible>>> {
-    let explicit_tty = query.client_tty.clone();
-    let shared_state = state.clone();
-
-    let stream = futures_util::stream::unfold(true, move |is_first| {
-        let explicit_tty = explicit_tty.clone();
-        let shared_state = shared_state.clone();
-        async move {
-            if !is_first {
-                tokio::time::sleep(Duration::from_secs(3)).await;
-            }
-
-            let client_tty = get_effective_client_tty(&shared_state, explicit_tty);
-            let tty_clone = client_tty.clone();
-
-            let payload = tokio::task::spawn_blocking(move || {
-                let cwd = get_cwd(tty_clone.clone());
-                let mut branch = String::new();
-                let mut path = cwd.clone();
-
-                if is_git_repo(&cwd) {
-                    let git_root = get_git_root(&cwd);
-                    branch = get_branch(&git_root);
-                    path = git_root;
-                }
+    let client_tty = get_effective_client_tty(&state, query.client_tty);
+    let diff_cache = state.diff_cache.clone();
 
-                let sessions = get_tmux_sessions();
-                let current_session = get_current_tmux_session(tty_clone.as_deref());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    std::thread::spawn(move || run_events_watcher(client_tty, diff_cache, tx));
 
-                serde_json::json!({
-                    "branch": branch,
-                    "path": path,
-                    "tmux": {
-                        "sessions": sessions,
-                        "currentSession": current_session,
-                    }
-                })
-            })
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
             .await
-            .unwrap_or_else(|_| serde_json::json!({}));
-
-            let event = Event::default().data(payload.to_string());
-            Some((Ok(event), false))
-        }
+            .map(|payload| (Ok(Event::default().data(payload.to_string())), rx))
     });
 
     Sse::new(stream).keep_alive(
@@ -1493,125 +2324,1438 @@ async fn api_events(
     )
 }
 
-// ─── POST /api/upload-image ────────────────────────────────────────────────
-
-async fn api_upload_image(req: Request) -> Response {
-    let content_type = req
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("")
-        .to_string();
+/// The repo root (if `cwd` is inside a git repo) or `cwd` itself — this is
+/// both what gets watched and what ends up in the `path` field of the SSE
+/// payload.
+fn git_root_or_cwd(cwd: &str) -> String {
+    if is_git_repo(cwd) {
+        get_git_root(cwd)
+    } else {
+        cwd.to_string()
+    }
+}
 
-    if !content_type.starts_with("image/") {
-        return json_error(
-            "invalid_content_type",
-            "Expected image/*",
-            StatusCode::BAD_REQUEST,
-        );
+/// Recomputes the branch/path pair shown in the SSE payload. Only called
+/// when something has actually changed (a debounced fs event, or the
+/// tracked cwd moving), not on a fixed timer.
+fn compute_git_state(cwd: &str) -> (String, String) {
+    if is_git_repo(cwd) {
+        let git_root = get_git_root(cwd);
+        (get_branch(&git_root), git_root)
+    } else {
+        (String::new(), cwd.to_string())
     }
+}
 
-    // Read body
-    let body_bytes = match axum::body::to_bytes(req.into_body(), 50 * 1024 * 1024).await {
-        Ok(b) => b,
-        Err(_) => {
-            return json_error("read_error", "Failed to read body", StatusCode::BAD_REQUEST)
+fn build_events_payload(branch: &str, path: &str, client_tty: Option<&str>) -> serde_json::Value {
+    serde_json::json!({
+        "branch": branch,
+        "path": path,
+        "tmux": {
+            "sessions": get_tmux_sessions(),
+            "currentSession": get_current_tmux_session(client_tty),
+        }
+    })
+}
+
+/// Runs on its own thread: watches the repo root (which covers `.git/HEAD`,
+/// `.git/refs`, the index, and the working tree in one recursive watch) and
+/// only recomputes branch/path when `notify` reports something actually
+/// changed, instead of re-shelling out to git on a fixed timer. `notify`
+/// can't see tmux session changes or the shell's tracked cwd moving to a
+/// different directory, so a short fallback tick covers just those two.
+fn run_events_watcher(
+    client_tty: Option<String>,
+    diff_cache: Arc<DiffCache>,
+    tx: tokio::sync::mpsc::UnboundedSender<serde_json::Value>,
+) {
+    use notify::{RecursiveMode, Watcher};
+
+    let (notify_tx, notify_rx) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
+    let mut watcher = match notify::recommended_watcher(move |res| {
+        let _ = notify_tx.send(res);
+    }) {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::error!("Failed to create events watcher: {}", e);
+            return;
         }
     };
 
-    if body_bytes.is_empty() {
-        return json_error("empty_body", "No image data", StatusCode::BAD_REQUEST);
+    let mut watched_cwd = get_cwd(client_tty.clone());
+    let mut watched_root = git_root_or_cwd(&watched_cwd);
+    if let Err(e) = watcher.watch(Path::new(&watched_root), RecursiveMode::Recursive) {
+        tracing::warn!("Failed to watch {}: {}", watched_root, e);
     }
 
-    // Determine extension
-    let ext = if content_type.contains("jpeg") || content_type.contains("jpg") {
-        "jpg"
-    } else if content_type.contains("gif") {
-        "gif"
-    } else if content_type.contains("webp") {
-        "webp"
-    } else {
-        "png"
-    };
+    let (mut branch, mut path) = compute_git_state(&watched_cwd);
+    if tx
+        .send(build_events_payload(&branch, &path, client_tty.as_deref()))
+        .is_err()
+    {
+        return;
+    }
 
-    // Create upload directory
-    let upload_dir = "/tmp/ttyd_images";
-    let _ = std::fs::create_dir_all(upload_dir);
+    let mut last_fallback_check = std::time::Instant::now();
+    let mut batch_deadline: Option<std::time::Instant> = None;
 
-    // Generate filename
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_millis();
-    let filename = format!("screenshot_{}.{}", timestamp, ext);
-    let filepath = format!("{}/{}", upload_dir, filename);
+    loop {
+        match notify_rx.recv_timeout(Duration::from_millis(250)) {
+            Ok(Ok(_event)) => batch_deadline = Some(std::time::Instant::now() + EVENTS_DEBOUNCE),
+            Ok(Err(e)) => tracing::warn!("Events watch error: {}", e),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
 
-    // Write file
-    match std::fs::write(&filepath, &body_bytes) {
-        Ok(_) => Json(serde_json::json!({
-            "path": filepath,
-            "filename": filename,
-        }))
-        .into_response(),
-        Err(e) => json_error(
-            "write_error",
-            &format!("Failed to write file: {}", e),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ),
-    }
-}
+        if let Some(deadline) = batch_deadline {
+            if std::time::Instant::now() >= deadline {
+                batch_deadline = None;
+                // Backstop for working-tree changes the diff cache's key
+                // doesn't reflect (e.g. a deleted file, whose mtime
+                // `git_worktree_mtime` can't read).
+                diff_cache.invalidate_repo(&watched_root);
+                (branch, path) = compute_git_state(&watched_cwd);
+                if tx
+                    .send(build_events_payload(&branch, &path, client_tty.as_deref()))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
 
-// ═══════════════════════════════════════════════════════════════════════════
-// DATA TYPES
-// ═══════════════════════════════════════════════════════════════════════════
+        if last_fallback_check.elapsed() > EVENTS_FALLBACK_POLL {
+            last_fallback_check = std::time::Instant::now();
 
-#[derive(Serialize)]
-struct BranchesResponse {
-    local: Vec<String>,
-    remote: Vec<String>,
-    current: String,
-}
+            let current_cwd = get_cwd(client_tty.clone());
+            if current_cwd != watched_cwd {
+                let new_root = git_root_or_cwd(&current_cwd);
+                if new_root != watched_root {
+                    let _ = watcher.unwatch(Path::new(&watched_root));
+                    if let Err(e) = watcher.watch(Path::new(&new_root), RecursiveMode::Recursive) {
+                        tracing::warn!("Failed to watch {}: {}", new_root, e);
+                    }
+                    watched_root = new_root;
+                }
+                watched_cwd = current_cwd;
+                (branch, path) = compute_git_state(&watched_cwd);
+            }
 
-#[derive(Serialize, Clone)]
-struct TmuxSession {
-    name: String,
-    windows: i32,
-    attached: bool,
+            if tx
+                .send(build_events_payload(&branch, &path, client_tty.as_deref()))
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
 }
 
-struct ChangedFile {
-    status: String,
-    filename: String,
-}
+// ─── GET /api/watch (SSE, notify-based file change stream) ────────────────
 
-#[derive(Serialize)]
-struct DiffLine {
-    #[serde(rename = "type")]
-    line_type: String, // "add", "del", "ctx"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    old_num: Option<i64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    new_num: Option<i64>,
-    content: String,
-}
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+const WATCH_REWATCH_POLL: Duration = Duration::from_secs(2);
 
-#[derive(Serialize)]
-struct DiffHunk {
-    header: String,
-    lines: Vec<DiffLine>,
+#[derive(Deserialize)]
+struct WatchQuery {
+    path: Option<String>,
+    client_tty: Option<String>,
 }
 
-#[derive(Serialize)]
-struct DiffFile {
-    filename: String,
-    status: String,
+async fn api_watch(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<WatchQuery>,
+) -> Response {
+    // Same confinement as `/api/fs/*`: an explicit path is untrusted client
+    // input and must be resolved under `fs_root` before we ever hand it to
+    // the `notify` watcher, or any client could stream changes from anywhere
+    // the server process can see (e.g. `?path=/etc`).
+    let explicit_path = match query.path {
+        Some(rel) => {
+            let root = fs_root(&state);
+            match resolve_fs_path(&root, &rel) {
+                Ok(p) => Some(p.to_string_lossy().into_owned()),
+                Err(resp) => return resp,
+            }
+        }
+        None => None,
+    };
+    let client_tty = get_effective_client_tty(&state, query.client_tty);
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    std::thread::spawn(move || run_fs_watcher(explicit_path, client_tty, tx));
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|payload| (Ok(Event::default().data(payload.to_string())), rx))
+    });
+
+    Sse::new(stream)
+        .keep_alive(
+            KeepAlive::new()
+                .interval(Duration::from_secs(15))
+                .text("keep-alive"),
+        )
+        .into_response()
+}
+
+/// Runs on its own thread: owns a `notify` watcher, coalesces bursts of
+/// filesystem events within `WATCH_DEBOUNCE`, and — when no explicit path
+/// was requested — re-registers the watch whenever the shell's tracked cwd
+/// moves on, so the frontend's file browser follows the terminal around.
+fn run_fs_watcher(
+    explicit_path: Option<String>,
+    client_tty: Option<String>,
+    tx: tokio::sync::mpsc::UnboundedSender<serde_json::Value>,
+) {
+    use notify::{RecursiveMode, Watcher};
+
+    let (notify_tx, notify_rx) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
+    let mut watcher = match notify::recommended_watcher(move |res| {
+        let _ = notify_tx.send(res);
+    }) {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::error!("Failed to create fs watcher: {}", e);
+            return;
+        }
+    };
+
+    let mut watched_path = explicit_path
+        .clone()
+        .unwrap_or_else(|| get_cwd(client_tty.clone()));
+    if let Err(e) = watcher.watch(Path::new(&watched_path), RecursiveMode::Recursive) {
+        tracing::warn!("Failed to watch {}: {}", watched_path, e);
+    }
+
+    let mut last_rewatch_check = std::time::Instant::now();
+    let mut pending: Vec<serde_json::Value> = Vec::new();
+    let mut batch_deadline: Option<std::time::Instant> = None;
+
+    loop {
+        if explicit_path.is_none() && last_rewatch_check.elapsed() > WATCH_REWATCH_POLL {
+            last_rewatch_check = std::time::Instant::now();
+            let current = get_cwd(client_tty.clone());
+            if current != watched_path {
+                let _ = watcher.unwatch(Path::new(&watched_path));
+                if let Err(e) = watcher.watch(Path::new(&current), RecursiveMode::Recursive) {
+                    tracing::warn!("Failed to watch {}: {}", current, e);
+                }
+                watched_path = current;
+            }
+        }
+
+        let timeout = batch_deadline
+            .map(|d| d.saturating_duration_since(std::time::Instant::now()))
+            .unwrap_or(Duration::from_millis(250));
+
+        match notify_rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if batch_deadline.is_none() {
+                    batch_deadline = Some(std::time::Instant::now() + WATCH_DEBOUNCE);
+                }
+                pending.push(serde_json::json!({
+                    "kind": format!("{:?}", event.kind),
+                    "paths": event
+                        .paths
+                        .iter()
+                        .map(|p| p.to_string_lossy())
+                        .collect::<Vec<_>>(),
+                }));
+            }
+            Ok(Err(e)) => tracing::warn!("Watch error: {}", e),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if let Some(deadline) = batch_deadline {
+            if std::time::Instant::now() >= deadline {
+                if !pending.is_empty() {
+                    let events = std::mem::take(&mut pending);
+                    let payload = serde_json::json!({ "path": watched_path, "events": events });
+                    if tx.send(payload).is_err() {
+                        break;
+                    }
+                }
+                batch_deadline = None;
+            }
+        }
+    }
+}
+
+// ─── Image storage backend ─────────────────────────────────────────────────
+
+/// Where uploaded screenshots end up. `api_upload_image` only ever talks
+/// to this trait, so swapping backends is a config change (`--storage-backend
+/// s3 ...`) rather than a code change.
+#[async_trait]
+trait ImageStore: Send + Sync {
+    /// Persists `bytes` (already re-encoded/stripped) under a file with
+    /// extension `ext`, returning an opaque identifier `retrieve` can use
+    /// to fetch it back later.
+    async fn store(&self, bytes: &[u8], ext: &str) -> Result<String, StoreError>;
+
+    /// Fetches back the bytes for an identifier previously returned by
+    /// `store`.
+    async fn retrieve(&self, id: &str) -> Result<Vec<u8>, StoreError>;
+
+    /// Removes the object. Backends treat a missing object as success
+    /// since the end state the caller wants (the object is gone) already
+    /// holds.
+    async fn delete(&self, id: &str) -> Result<(), StoreError>;
+}
+
+#[derive(Debug)]
+enum StoreError {
+    NotFound,
+    Io(std::io::Error),
+    Backend(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::NotFound => write!(f, "not found"),
+            StoreError::Io(e) => write!(f, "io error: {}", e),
+            StoreError::Backend(msg) => write!(f, "backend error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+/// Builds the configured `ImageStore` from CLI flags. Panics on startup if
+/// `--storage-backend s3` is selected without the required S3 settings,
+/// same as any other misconfigured-at-boot flag in this binary.
+fn build_image_store(cli: &Cli) -> Arc<dyn ImageStore> {
+    match cli.storage_backend.as_str() {
+        "local" => Arc::new(LocalImageStore {
+            dir: cli.upload_dir.clone(),
+        }),
+        "s3" => {
+            let endpoint = cli
+                .s3_endpoint
+                .clone()
+                .expect("--s3-endpoint is required when --storage-backend=s3");
+            let bucket = cli
+                .s3_bucket
+                .clone()
+                .expect("--s3-bucket is required when --storage-backend=s3");
+            let access_key = cli
+                .s3_access_key
+                .clone()
+                .expect("--s3-access-key is required when --storage-backend=s3");
+            let secret_key = cli
+                .s3_secret_key
+                .clone()
+                .expect("--s3-secret-key is required when --storage-backend=s3");
+            Arc::new(S3ImageStore {
+                endpoint,
+                bucket,
+                region: cli.s3_region.clone(),
+                access_key,
+                secret_key,
+                client: reqwest::Client::new(),
+            })
+        }
+        other => panic!("unknown --storage-backend '{}' (expected 'local' or 's3')", other),
+    }
+}
+
+/// Writes uploads straight to a directory on the local filesystem. This is
+/// the default and matches the original hardcoded `/tmp/ttyd_images`
+/// behavior, just configurable now.
+struct LocalImageStore {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl ImageStore for LocalImageStore {
+    async fn store(&self, bytes: &[u8], ext: &str) -> Result<String, StoreError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let id = format!("screenshot_{}.{}", timestamp, ext);
+        tokio::fs::write(self.dir.join(&id), bytes).await?;
+        Ok(id)
+    }
+
+    async fn retrieve(&self, id: &str) -> Result<Vec<u8>, StoreError> {
+        match tokio::fs::read(self.dir.join(id)).await {
+            Ok(bytes) => Ok(bytes),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StoreError::NotFound),
+            Err(e) => Err(StoreError::Io(e)),
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), StoreError> {
+        match tokio::fs::remove_file(self.dir.join(id)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(StoreError::Io(e)),
+        }
+    }
+}
+
+/// Writes uploads to an S3-compatible bucket (AWS S3, MinIO, R2, ...),
+/// addressed by endpoint/bucket/region rather than a vendor-specific SDK
+/// so it works against any compatible provider.
+struct S3ImageStore {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: reqwest::Client,
+}
+
+impl S3ImageStore {
+    fn object_url(&self, id: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.endpoint.trim_end_matches('/'),
+            self.bucket,
+            id
+        )
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3ImageStore {
+    async fn store(&self, bytes: &[u8], ext: &str) -> Result<String, StoreError> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let id = format!("screenshot_{}.{}", timestamp, ext);
+
+        let url = self.object_url(&id);
+        let headers = sign_s3_request(
+            "PUT",
+            &url,
+            bytes,
+            &self.region,
+            &self.access_key,
+            &self.secret_key,
+        )
+        .map_err(StoreError::Backend)?;
+
+        let mut req = self.client.put(&url).body(bytes.to_vec());
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(StoreError::Backend(format!(
+                "S3 PUT failed with status {}",
+                resp.status()
+            )));
+        }
+        Ok(id)
+    }
+
+    async fn retrieve(&self, id: &str) -> Result<Vec<u8>, StoreError> {
+        let url = self.object_url(id);
+        let headers = sign_s3_request("GET", &url, &[], &self.region, &self.access_key, &self.secret_key)
+            .map_err(StoreError::Backend)?;
+
+        let mut req = self.client.get(&url);
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(StoreError::NotFound);
+        }
+        if !resp.status().is_success() {
+            return Err(StoreError::Backend(format!(
+                "S3 GET failed with status {}",
+                resp.status()
+            )));
+        }
+        resp.bytes()
+            .await
+            .map(|b| b.to_vec())
+            .map_err(|e| StoreError::Backend(e.to_string()))
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), StoreError> {
+        let url = self.object_url(id);
+        let headers = sign_s3_request(
+            "DELETE",
+            &url,
+            &[],
+            &self.region,
+            &self.access_key,
+            &self.secret_key,
+        )
+        .map_err(StoreError::Backend)?;
+
+        let mut req = self.client.delete(&url);
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        if !resp.status().is_success() && resp.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(StoreError::Backend(format!(
+                "S3 DELETE failed with status {}",
+                resp.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Minimal AWS SigV4 signer covering the single-shot PUT/GET/DELETE
+/// requests this backend needs — full multipart/chunked upload support
+/// isn't worth the complexity for screenshot-sized payloads.
+fn sign_s3_request(
+    method: &str,
+    url: &str,
+    body: &[u8],
+    region: &str,
+    access_key: &str,
+    secret_key: &str,
+) -> Result<Vec<(String, String)>, String> {
+    type HmacSha256 = Hmac<Sha256>;
+
+    let parsed = url::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = parsed.host_str().ok_or("S3 URL has no host")?;
+    // The Host header sent on the wire carries a non-default port (e.g.
+    // MinIO on :9000), so the signed value has to include it too.
+    let host = match parsed.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = parsed.path().to_string();
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let datetime = format_amz_datetime(now);
+    let date = &datetime[..8];
+
+    let payload_hash = hex::encode(Sha256::digest(body));
+
+    let canonical_headers = format!(
+        "host:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n",
+        host, payload_hash, datetime
+    );
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, payload_hash
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        datetime,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let sign = |key: &[u8], msg: &str| -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(msg.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+
+    let k_date = sign(format!("AWS4{}", secret_key).as_bytes(), date);
+    let k_region = sign(&k_date, region);
+    let k_service = sign(&k_region, "s3");
+    let k_signing = sign(&k_service, "aws4_request");
+    let signature = hex::encode(sign(&k_signing, &string_to_sign));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key, scope, signed_headers, signature
+    );
+
+    Ok(vec![
+        ("x-amz-date".to_string(), datetime),
+        ("x-amz-content-sha256".to_string(), payload_hash),
+        ("authorization".to_string(), authorization),
+    ])
+}
+
+/// Formats a unix timestamp as an S3 SigV4 `YYYYMMDDTHHMMSSZ` string
+/// without pulling in a full datetime crate.
+fn format_amz_datetime(unix_secs: u64) -> String {
+    const DAYS_BY_MONTH: [i64; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+    let days_since_epoch = (unix_secs / 86400) as i64;
+    let secs_of_day = unix_secs % 86400;
+
+    let mut year = 1970i64;
+    let mut remaining_days = days_since_epoch;
+    loop {
+        let is_leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+        let days_in_year = if is_leap { 366 } else { 365 };
+        if remaining_days < days_in_year {
+            break;
+        }
+        remaining_days -= days_in_year;
+        year += 1;
+    }
+
+    let is_leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let mut month = 0usize;
+    for (i, &days) in DAYS_BY_MONTH.iter().enumerate() {
+        let days = if i == 1 && is_leap { days + 1 } else { days };
+        if remaining_days < days {
+            month = i;
+            break;
+        }
+        remaining_days -= days;
+    }
+    let day = remaining_days + 1;
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month + 1,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60
+    )
+}
+
+// ─── Upload registry (dedup + delete tokens) ───────────────────────────────
+
+/// Tracks two mappings in an embedded sled database so upload state
+/// survives restarts: content-hash → blob (so re-uploading identical
+/// bytes reuses the existing stored object instead of writing a new
+/// copy) and alias → blob (so every upload still gets its own delete
+/// token, even ones that share a blob via dedup).
+struct UploadRegistry {
+    blobs: sled::Tree,
+    aliases: sled::Tree,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlobRecord {
+    storage_id: String,
+    ref_count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AliasRecord {
+    content_hash: String,
+    storage_id: String,
+    delete_token: String,
+}
+
+impl UploadRegistry {
+    fn open(path: &Path) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(UploadRegistry {
+            blobs: db.open_tree("blobs")?,
+            aliases: db.open_tree("aliases")?,
+        })
+    }
+
+    fn lookup_blob(&self, hash: &str) -> Option<BlobRecord> {
+        self.blobs
+            .get(hash)
+            .ok()
+            .flatten()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+    }
+
+    /// Atomically creates the blob record for a freshly stored object, or —
+    /// if another upload raced it and already created one for the same
+    /// content hash — bumps that record's ref count instead and returns its
+    /// `storage_id`. Unlike a plain `lookup_blob` + `insert_blob`, this is a
+    /// single `fetch_and_update` (sled retries it internally on contention),
+    /// so two concurrent uploads of identical bytes can't both observe a
+    /// miss and overwrite each other's ref count with 1.
+    fn insert_or_incr_blob(&self, hash: &str, storage_id: &str) -> String {
+        let mut result_storage_id = storage_id.to_string();
+        let _ = self.blobs.fetch_and_update(hash, |old| match old {
+            Some(bytes) => {
+                let mut record: BlobRecord = serde_json::from_slice(bytes).ok()?;
+                record.ref_count += 1;
+                result_storage_id = record.storage_id.clone();
+                serde_json::to_vec(&record).ok()
+            }
+            None => {
+                result_storage_id = storage_id.to_string();
+                serde_json::to_vec(&BlobRecord {
+                    storage_id: storage_id.to_string(),
+                    ref_count: 1,
+                })
+                .ok()
+            }
+        });
+        result_storage_id
+    }
+
+    /// Bumps an existing blob's reference count, returning the new count.
+    fn incr_ref(&self, hash: &str) -> Option<u64> {
+        let mut new_count = None;
+        let _ = self.blobs.fetch_and_update(hash, |old| {
+            let mut record: BlobRecord = serde_json::from_slice(old?).ok()?;
+            record.ref_count += 1;
+            new_count = Some(record.ref_count);
+            serde_json::to_vec(&record).ok()
+        });
+        new_count
+    }
+
+    /// Drops a blob's reference count by one, removing its record once
+    /// the count reaches zero. Returns the new count.
+    fn decr_ref(&self, hash: &str) -> u64 {
+        let mut new_count = 0u64;
+        let _ = self.blobs.fetch_and_update(hash, |old| {
+            let mut record: BlobRecord = serde_json::from_slice(old?).ok()?;
+            record.ref_count = record.ref_count.saturating_sub(1);
+            new_count = record.ref_count;
+            if record.ref_count == 0 {
+                None
+            } else {
+                serde_json::to_vec(&record).ok()
+            }
+        });
+        new_count
+    }
+
+    fn insert_alias(&self, id: &str, record: &AliasRecord) {
+        if let Ok(bytes) = serde_json::to_vec(record) {
+            let _ = self.aliases.insert(id, bytes);
+        }
+    }
+
+    fn get_alias(&self, id: &str) -> Option<AliasRecord> {
+        self.aliases
+            .get(id)
+            .ok()
+            .flatten()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+    }
+
+    fn remove_alias(&self, id: &str) {
+        let _ = self.aliases.remove(id);
+    }
+}
+
+/// Generates an unguessable delete token; only the uploader who received
+/// it in the upload response can remove that alias.
+fn generate_delete_token() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// ─── POST /api/upload-image ────────────────────────────────────────────────
+
+async fn api_upload_image(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    req: Request,
+) -> Response {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    if !content_type.starts_with("image/") {
+        return json_error(
+            "invalid_content_type",
+            "Expected image/*",
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    // Read body
+    let body_bytes = match axum::body::to_bytes(req.into_body(), 50 * 1024 * 1024).await {
+        Ok(b) => b,
+        Err(_) => {
+            return json_error("read_error", "Failed to read body", StatusCode::BAD_REQUEST)
+        }
+    };
+
+    if body_bytes.is_empty() {
+        return json_error("empty_body", "No image data", StatusCode::BAD_REQUEST);
+    }
+
+    // Determine the *declared* extension from the header, then verify it
+    // against the file's actual magic bytes — never trust Content-Type.
+    let declared_ext = if content_type.contains("jpeg") || content_type.contains("jpg") {
+        "jpg"
+    } else if content_type.contains("gif") {
+        "gif"
+    } else if content_type.contains("webp") {
+        "webp"
+    } else {
+        "png"
+    };
+
+    let real_ext = match sniff_image_format(&body_bytes) {
+        Some(ext) => ext,
+        None => {
+            return json_error(
+                "unrecognized_format",
+                "Could not identify an image format from the file contents",
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+
+    if real_ext != declared_ext {
+        return json_error(
+            "format_mismatch",
+            &format!(
+                "Content-Type claimed '{}' but the file contents are '{}'",
+                declared_ext, real_ext
+            ),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let img = match image::load_from_memory(&body_bytes) {
+        Ok(img) => img,
+        Err(e) => {
+            return json_error(
+                "decode_failed",
+                &format!("Failed to decode image: {}", e),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+    let (width, height) = img.dimensions();
+    let blurhash = encode_blurhash(&img, 4, 3);
+
+    // Re-encoding through the `image` crate rebuilds the file from raw
+    // pixel data, which drops EXIF/GPS and other metadata chunks that
+    // aren't part of the image itself.
+    let format = image_format_for_ext(real_ext).unwrap_or(image::ImageFormat::Png);
+    let mut stripped = Vec::new();
+    if let Err(e) = img.write_to(&mut std::io::Cursor::new(&mut stripped), format) {
+        return json_error(
+            "encode_failed",
+            &format!("Failed to re-encode image: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+
+    // Content-address the blob so re-uploading identical bytes reuses the
+    // existing stored object instead of writing (and paying to store) a
+    // duplicate copy.
+    let content_hash = blake3::hash(&stripped).to_hex().to_string();
+    let storage_id = match state.uploads.lookup_blob(&content_hash) {
+        Some(existing) => {
+            state.uploads.incr_ref(&content_hash);
+            existing.storage_id
+        }
+        None => match state.image_store.store(&stripped, real_ext).await {
+            Ok(id) => {
+                let winning_id = state.uploads.insert_or_incr_blob(&content_hash, &id);
+                if winning_id != id {
+                    // Lost a race with a concurrent upload of the same
+                    // content: our copy is orphaned, the other upload's
+                    // blob record is the one that stuck.
+                    if let Err(e) = state.image_store.delete(&id).await {
+                        tracing::warn!("Failed to delete orphaned blob {}: {}", id, e);
+                    }
+                }
+                winning_id
+            }
+            Err(e) => {
+                return json_error(
+                    "store_error",
+                    &format!("Failed to store image: {}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        },
+    };
+
+    // Every upload gets its own alias and delete token even when it
+    // shares a blob with another upload via dedup above.
+    let alias_id = format!(
+        "img_{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    );
+    let delete_token = generate_delete_token();
+    state.uploads.insert_alias(
+        &alias_id,
+        &AliasRecord {
+            content_hash,
+            storage_id: storage_id.clone(),
+            delete_token: delete_token.clone(),
+        },
+    );
+
+    Json(serde_json::json!({
+        "id": alias_id,
+        "filename": storage_id,
+        "url": format!("/api/upload-image/{}", alias_id),
+        "format": real_ext,
+        "width": width,
+        "height": height,
+        "bytes": stripped.len(),
+        "blurhash": blurhash,
+        "delete_token": delete_token,
+    }))
+    .into_response()
+}
+
+/// Serves back the bytes for a previously uploaded image, fetched through
+/// the configured `ImageStore` so this works the same way against both the
+/// local and S3 backends.
+async fn api_get_image(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Response {
+    let Some(alias) = state.uploads.get_alias(&id) else {
+        return json_error("not_found", "No such upload", StatusCode::NOT_FOUND);
+    };
+
+    match state.image_store.retrieve(&alias.storage_id).await {
+        Ok(bytes) => (
+            [(header::CONTENT_TYPE, mime_for_path(Path::new(&alias.storage_id)))],
+            bytes,
+        )
+            .into_response(),
+        Err(StoreError::NotFound) => {
+            json_error("not_found", "Stored image is missing", StatusCode::NOT_FOUND)
+        }
+        Err(e) => json_error(
+            "retrieve_error",
+            &format!("Failed to retrieve image: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+/// Removes an uploaded image's alias, and if it was the last alias
+/// pointing at that blob, removes the blob itself from the storage
+/// backend. Requires the delete token issued in the original upload
+/// response.
+async fn api_delete_image(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Query(query): Query<DeleteImageQuery>,
+) -> Response {
+    let Some(alias) = state.uploads.get_alias(&id) else {
+        return json_error("not_found", "No such upload", StatusCode::NOT_FOUND);
+    };
+
+    if !constant_time_eq(alias.delete_token.as_bytes(), query.token.as_bytes()) {
+        return json_error(
+            "invalid_token",
+            "Delete token does not match",
+            StatusCode::FORBIDDEN,
+        );
+    }
+
+    state.uploads.remove_alias(&id);
+    let remaining = state.uploads.decr_ref(&alias.content_hash);
+    if remaining == 0 {
+        if let Err(e) = state.image_store.delete(&alias.storage_id).await {
+            tracing::warn!("Failed to delete blob {}: {}", alias.storage_id, e);
+        }
+    }
+
+    Json(serde_json::json!({ "deleted": true })).into_response()
+}
+
+#[derive(Deserialize)]
+struct DeleteImageQuery {
+    token: String,
+}
+
+/// Identifies an image's real format from its magic bytes, ignoring
+/// whatever the client's Content-Type header claimed.
+fn sniff_image_format(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("jpg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+fn image_format_for_ext(ext: &str) -> Option<image::ImageFormat> {
+    match ext {
+        "png" => Some(image::ImageFormat::Png),
+        "jpg" | "jpeg" => Some(image::ImageFormat::Jpeg),
+        "gif" => Some(image::ImageFormat::Gif),
+        "webp" => Some(image::ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+// ─── Blurhash ───────────────────────────────────────────────────────────────
+//
+// A compact placeholder string the frontend can render as a blurred
+// preview while the full image loads. See
+// https://github.com/woltapp/blurhash for the reference algorithm this
+// mirrors.
+
+const BASE83_CHARS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn encode_base83(mut value: u32, length: usize) -> String {
+    let mut out = vec![0u8; length];
+    for i in (0..length).rev() {
+        out[i] = BASE83_CHARS[(value % 83) as usize];
+        value /= 83;
+    }
+    String::from_utf8(out).expect("base83 charset is ASCII")
+}
+
+fn srgb_to_linear(c: u8) -> f64 {
+    let v = c as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> u8 {
+    let v = v.clamp(0.0, 1.0);
+    let srgb = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0 + 0.5).floor().clamp(0.0, 255.0) as u8
+}
+
+/// Sums `linear_rgb(x, y) * cos(pi*i*x/width) * cos(pi*j*y/height)` over
+/// every pixel for basis `(i, j)`, normalized by pixel count (the DC term
+/// `i = j = 0` gets factor 1, every AC term gets factor 2).
+fn blurhash_basis_factor(pixels: &[[f64; 3]], width: u32, height: u32, i: u32, j: u32) -> [f64; 3] {
+    let mut sum = [0.0_f64; 3];
+    for y in 0..height {
+        for x in 0..width {
+            let basis = (std::f64::consts::PI * i as f64 * x as f64 / width as f64).cos()
+                * (std::f64::consts::PI * j as f64 * y as f64 / height as f64).cos();
+            let px = pixels[(y * width + x) as usize];
+            sum[0] += basis * px[0];
+            sum[1] += basis * px[1];
+            sum[2] += basis * px[2];
+        }
+    }
+    let normalization = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+    let scale = normalization / (width * height) as f64;
+    [sum[0] * scale, sum[1] * scale, sum[2] * scale]
+}
+
+fn blurhash_quantize_ac(value: f64, max_ac: f64) -> u32 {
+    let v = (value / max_ac).clamp(-1.0, 1.0);
+    // Apply the sign to the exponentiated magnitude *before* the +9.5
+    // offset, not to the already-offset result — otherwise every
+    // negative coefficient collapses to the clamped floor (0) instead of
+    // landing in the low end of the 0..=18 range it should map to.
+    let signed_pow = v.signum() * v.abs().powf(0.5);
+    (signed_pow * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+}
+
+#[cfg(test)]
+mod blurhash_tests {
+    use super::blurhash_quantize_ac;
+
+    #[test]
+    fn quantize_ac_maps_negative_coefficients_below_the_midpoint() {
+        // A strongly negative coefficient should land near the low end
+        // of the range, not collapse to 0 for every negative input.
+        let strong_negative = blurhash_quantize_ac(-1.0, 1.0);
+        let weak_negative = blurhash_quantize_ac(-0.01, 1.0);
+        assert!(strong_negative < weak_negative);
+        assert_eq!(strong_negative, 0);
+        assert_eq!(weak_negative, 8);
+    }
+}
+
+/// Encodes `img` as a blurhash string with `components_x` × `components_y`
+/// frequency components (each in `1..=9`).
+fn encode_blurhash(img: &image::DynamicImage, components_x: u32, components_y: u32) -> String {
+    let rgb = img.to_rgb8();
+    let (width, height) = rgb.dimensions();
+
+    let pixels: Vec<[f64; 3]> = rgb
+        .pixels()
+        .map(|p| {
+            [
+                srgb_to_linear(p[0]),
+                srgb_to_linear(p[1]),
+                srgb_to_linear(p[2]),
+            ]
+        })
+        .collect();
+
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            factors.push(blurhash_basis_factor(&pixels, width, height, i, j));
+        }
+    }
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let size_flag = (components_x - 1) + (components_y - 1) * 9;
+    let mut result = encode_base83(size_flag, 1);
+
+    let max_ac_value = ac
+        .iter()
+        .flatten()
+        .fold(0.0_f64, |max, &v| max.max(v.abs()));
+    let quantized_max_ac = if max_ac_value > 0.0 {
+        ((max_ac_value * 166.0 - 0.5).floor() as i64).clamp(0, 82) as u32
+    } else {
+        0
+    };
+    let actual_max_ac = (quantized_max_ac + 1) as f64 / 166.0;
+    result.push_str(&encode_base83(quantized_max_ac, 1));
+
+    let dc_value = (linear_to_srgb(dc[0]) as u32) << 16
+        | (linear_to_srgb(dc[1]) as u32) << 8
+        | linear_to_srgb(dc[2]) as u32;
+    result.push_str(&encode_base83(dc_value, 4));
+
+    for component in ac {
+        let encoded = component
+            .iter()
+            .fold(0u32, |acc, &v| acc * 19 + blurhash_quantize_ac(v, actual_max_ac));
+        result.push_str(&encode_base83(encoded, 2));
+    }
+
+    result
+}
+
+// ═══════════════════════════════════════════════════════════════════════════
+// FILESYSTEM API (/api/fs/*)
+// ═══════════════════════════════════════════════════════════════════════════
+//
+// All paths are relative to `fs_root`, which defaults to the shell's tracked
+// cwd so the mobile frontend browses "where the terminal is" without extra
+// configuration. Every resolved path is checked against the canonicalized
+// root before use so `../../etc/passwd`-style traversal can't escape it.
+
+fn fs_root(state: &AppState) -> PathBuf {
+    state
+        .fs_root
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(get_cwd(get_client_tty_from_state(state))))
+}
+
+/// Joins `rel_path` onto `root` and confirms the result stays inside it.
+/// Canonicalizes the deepest existing ancestor (rather than requiring the
+/// whole path to exist) so this also works for `fs/write`, whose target
+/// file doesn't exist yet.
+fn resolve_fs_path(root: &Path, rel_path: &str) -> Result<PathBuf, Response> {
+    let rel_path = rel_path.trim_start_matches('/');
+    if rel_path.is_empty() {
+        return root.canonicalize().map_err(|_| {
+            json_error(
+                "invalid_root",
+                "Configured fs root does not exist",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        });
+    }
+
+    let mut existing = root.join(rel_path);
+    let mut remainder: Vec<std::ffi::OsString> = Vec::new();
+    while !existing.exists() {
+        match existing.file_name().map(|n| n.to_os_string()) {
+            Some(name) => {
+                remainder.push(name);
+                existing.pop();
+            }
+            None => break,
+        }
+    }
+
+    let canonical_root = root.canonicalize().map_err(|_| {
+        json_error(
+            "invalid_root",
+            "Configured fs root does not exist",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })?;
+    let canonical_existing = existing.canonicalize().map_err(|_| {
+        json_error(
+            "invalid_path",
+            "Path does not exist",
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+
+    if !canonical_existing.starts_with(&canonical_root) {
+        return Err(json_error(
+            "forbidden",
+            "Path escapes the allowed root",
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    let mut resolved = canonical_existing;
+    for part in remainder.into_iter().rev() {
+        resolved.push(part);
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod resolve_fs_path_tests {
+    use super::resolve_fs_path;
+    use std::path::PathBuf;
+
+    /// A fresh `<tmp>/resolve_fs_path_tests_<nanos>/root` with a `child`
+    /// file inside it, plus an `outside` sibling directory a traversal
+    /// attempt could try to reach.
+    fn test_root() -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let base = std::env::temp_dir().join(format!("resolve_fs_path_tests_{}", nanos));
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(root.join("child"), b"hello").unwrap();
+        std::fs::write(base.join("outside").join("secret"), b"nope").unwrap();
+        root
+    }
+
+    #[test]
+    fn rejects_traversal_outside_the_root() {
+        let root = test_root();
+        let result = resolve_fs_path(&root, "../outside/secret");
+        assert!(result.is_err(), "escaping the root should be rejected");
+    }
+
+    #[test]
+    fn rejects_absolute_style_traversal() {
+        let root = test_root();
+        let result = resolve_fs_path(&root, "../../../../../../etc/passwd");
+        assert!(result.is_err(), "deep traversal should be rejected");
+    }
+
+    #[test]
+    fn allows_a_path_that_stays_inside_the_root() {
+        let root = test_root();
+        let resolved = resolve_fs_path(&root, "child").expect("in-root path should resolve");
+        assert_eq!(resolved, root.canonicalize().unwrap().join("child"));
+    }
+}
+
+#[derive(Deserialize)]
+struct FsPathQuery {
+    path: Option<String>,
+}
+
+// ─── GET /api/fs/list ───────────────────────────────────────────────────────
+
+async fn api_fs_list(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<FsPathQuery>,
+) -> Response {
+    let rel = query.path.unwrap_or_default();
+    let root = fs_root(&state);
+    let target = match resolve_fs_path(&root, &rel) {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+
+    let entries = match std::fs::read_dir(&target) {
+        Ok(e) => e,
+        Err(e) => {
+            return json_error(
+                "read_dir_failed",
+                &e.to_string(),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+
+    let items: Vec<serde_json::Value> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            Some(serde_json::json!({
+                "name": entry.file_name().to_string_lossy(),
+                "size": meta.len(),
+                "is_dir": meta.is_dir(),
+                "mtime": mtime,
+            }))
+        })
+        .collect();
+
+    Json(serde_json::json!({ "path": rel, "entries": items })).into_response()
+}
+
+// ─── GET /api/fs/read ───────────────────────────────────────────────────────
+
+async fn api_fs_read(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<FsPathQuery>,
+) -> Response {
+    let rel = match query.path {
+        Some(p) if !p.is_empty() => p,
+        _ => return json_error("missing_path", "path required", StatusCode::BAD_REQUEST),
+    };
+    let root = fs_root(&state);
+    let target = match resolve_fs_path(&root, &rel) {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+
+    if !target.is_file() {
+        return json_error(
+            "not_a_file",
+            "Path is not a regular file",
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    match tokio::fs::read(&target).await {
+        Ok(bytes) => ([(header::CONTENT_TYPE, mime_for_path(&target))], bytes).into_response(),
+        Err(e) => json_error("read_failed", &e.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// ─── POST /api/fs/write ─────────────────────────────────────────────────────
+
+async fn api_fs_write(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<FsPathQuery>,
+    req: Request,
+) -> Response {
+    let rel = match query.path {
+        Some(p) if !p.is_empty() => p,
+        _ => return json_error("missing_path", "path required", StatusCode::BAD_REQUEST),
+    };
+    let root = fs_root(&state);
+    let target = match resolve_fs_path(&root, &rel) {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+
+    let body_bytes = match axum::body::to_bytes(req.into_body(), 50 * 1024 * 1024).await {
+        Ok(b) => b,
+        Err(_) => {
+            return json_error("read_error", "Failed to read body", StatusCode::BAD_REQUEST)
+        }
+    };
+
+    if let Some(parent) = target.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+
+    match std::fs::write(&target, &body_bytes) {
+        Ok(_) => Json(serde_json::json!({
+            "success": true,
+            "path": rel,
+            "bytes": body_bytes.len(),
+        }))
+        .into_response(),
+        Err(e) => json_error(
+            "write_error",
+            &format!("Failed to write file: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+// ═══════════════════════════════════════════════════════════════════════════
+// DATA TYPES
+// ═══════════════════════════════════════════════════════════════════════════
+
+#[derive(Serialize)]
+struct BranchesResponse {
+    local: Vec<String>,
+    remote: Vec<String>,
+    current: String,
+}
+
+#[derive(Serialize, Clone)]
+struct TmuxSession {
+    name: String,
+    windows: i32,
+    attached: bool,
+}
+
+struct ChangedFile {
+    status: String,
+    filename: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiffLine {
+    #[serde(rename = "type")]
+    line_type: String, // "add", "del", "ctx"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_num: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_num: Option<i64>,
+    content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiffHunk {
+    header: String,
+    lines: Vec<DiffLine>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiffFile {
+    filename: String,
+    status: String,
     binary: bool,
     additions: i64,
     deletions: i64,
     hunks: Vec<DiffHunk>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 struct DiffSummary {
     #[serde(rename = "totalFiles")]
     total_files: i64,
@@ -1621,6 +3765,7 @@ struct DiffSummary {
     total_deletions: i64,
 }
 
+#[derive(Serialize, Deserialize)]
 struct DiffResult {
     files: Vec<DiffFile>,
     summary: DiffSummary,
@@ -1630,39 +3775,135 @@ struct DiffResult {
 // SUBPROCESS HELPERS
 // ═══════════════════════════════════════════════════════════════════════════
 
-fn run_cmd(cmd: &str, args: &[&str]) -> Result<String, String> {
-    match StdCommand::new(cmd)
-        .args(args)
-        .env_remove("TMUX")
-        .env_remove("TMUX_PANE")
-        .output()
-    {
-        Ok(output) => {
-            if output.status.success() {
-                Ok(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
-            }
+/// Bound applied to subprocess calls going through `run_cmd`/`run_cmd_in` —
+/// generous enough for routine git/tmux queries, short enough that a hung
+/// subprocess can't wedge an SSE stream's `spawn_blocking` task forever.
+const DEFAULT_CMD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Large repositories can take far longer to diff than a routine git
+/// query, so `get_files_diff` asks for this instead of sharing
+/// `DEFAULT_CMD_TIMEOUT`.
+const DIFF_CMD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Distinguishes a command that was killed for running too long from one
+/// that simply exited with a nonzero status, so callers (and their error
+/// messages) can tell the two apart.
+#[derive(Debug)]
+enum CmdError {
+    TimedOut,
+    NonZeroExit(String),
+}
+
+impl std::fmt::Display for CmdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CmdError::TimedOut => write!(f, "command timed out"),
+            CmdError::NonZeroExit(stderr) => write!(f, "{}", stderr),
         }
-        Err(e) => Err(e.to_string()),
     }
 }
 
+fn run_cmd(cmd: &str, args: &[&str]) -> Result<String, String> {
+    run_cmd_with_timeout(cmd, args, None, DEFAULT_CMD_TIMEOUT).map_err(|e| e.to_string())
+}
+
 fn run_cmd_in(cmd: &str, args: &[&str], cwd: &str) -> Result<String, String> {
-    match StdCommand::new(cmd)
+    run_cmd_with_timeout(cmd, args, Some(cwd), DEFAULT_CMD_TIMEOUT).map_err(|e| e.to_string())
+}
+
+/// Same as `run_cmd_in`, but lets the caller pick a timeout instead of
+/// `DEFAULT_CMD_TIMEOUT` — used where a command is known to need more
+/// headroom, such as diffing a large repo.
+fn run_cmd_in_with_timeout(
+    cmd: &str,
+    args: &[&str],
+    cwd: &str,
+    timeout: Duration,
+) -> Result<String, CmdError> {
+    run_cmd_with_timeout(cmd, args, Some(cwd), timeout)
+}
+
+/// Spawns `cmd` in its own process group, waits up to `timeout`, and kills
+/// the whole group on expiry so shell-spawned grandchildren don't outlive
+/// the parent being killed. Stdout/stderr are drained on background
+/// threads while we wait so a chatty command can't fill its pipe and
+/// deadlock before the timeout is even reached.
+fn run_cmd_with_timeout(
+    cmd: &str,
+    args: &[&str],
+    cwd: Option<&str>,
+    timeout: Duration,
+) -> Result<String, CmdError> {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = StdCommand::new(cmd);
+    command
         .args(args)
-        .current_dir(cwd)
         .env_remove("TMUX")
         .env_remove("TMUX_PANE")
-        .output()
-    {
-        Ok(output) => {
-            if output.status.success() {
-                Ok(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .process_group(0);
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => return Err(CmdError::NonZeroExit(e.to_string())),
+    };
+    let pgid = child.id() as i32;
+
+    let mut stdout_pipe = child.stdout.take();
+    let stdout_handle = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(pipe) = stdout_pipe.as_mut() {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    });
+    let mut stderr_pipe = child.stderr.take();
+    let stderr_handle = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(pipe) = stderr_pipe.as_mut() {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    });
+
+    let deadline = std::time::Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) => {
+                if std::time::Instant::now() >= deadline {
+                    break None;
+                }
+                std::thread::sleep(Duration::from_millis(20));
             }
+            Err(_) => break None,
+        }
+    };
+
+    let timed_out = status.is_none();
+    if timed_out {
+        // SAFETY: `pgid` is this child's own process group (it was spawned
+        // with `process_group(0)`), so negating it targets exactly that
+        // group rather than some unrelated process.
+        unsafe {
+            libc::kill(-pgid, libc::SIGKILL);
         }
-        Err(e) => Err(e.to_string()),
+    }
+    let _ = child.wait();
+
+    let stdout = stdout_handle.join().unwrap_or_default();
+    let stderr = stderr_handle.join().unwrap_or_default();
+
+    match status {
+        None => Err(CmdError::TimedOut),
+        Some(status) if status.success() => Ok(String::from_utf8_lossy(&stdout).to_string()),
+        Some(_) => Err(CmdError::NonZeroExit(
+            String::from_utf8_lossy(&stderr).trim().to_string(),
+        )),
     }
 }